@@ -0,0 +1,77 @@
+use std::ffi::{CStr,CString};
+
+use c_interface::QemuOpts;
+use c_interface::functions;
+
+
+/// The kind of value a `bdrv_create()` option holds, mirroring
+/// `QemuOptType`.
+#[repr(C)]
+#[derive(Clone,Copy)]
+pub enum QemuOptType {
+    String = 0,
+    Bool = 1,
+    Number = 2,
+    Size = 3,
+}
+
+/// One entry of a driver's `create_opts`: mirrors `QemuOptDesc`.
+#[repr(C)]
+pub struct QemuOptDesc {
+    pub name: *const u8,
+    pub opt_type: QemuOptType,
+    pub help: *const u8,
+}
+
+impl QemuOptDesc {
+    /// `name` and `help` are leaked (never freed): `create_opts` lives
+    /// for the lifetime of the process once a driver is registered, same
+    /// as the `BlockDriver` itself (see `bdrv_register()`).
+    pub fn new(name: &str, opt_type: QemuOptType, help: &str) -> QemuOptDesc
+    {
+        QemuOptDesc {
+            name: CString::new(name).unwrap().into_raw() as *const u8,
+            opt_type: opt_type,
+            help: CString::new(help).unwrap().into_raw() as *const u8,
+        }
+    }
+}
+
+
+/// Safe view of the `QemuOpts` a `BlockDriverOps::create()` implementation
+/// is handed.
+pub struct CreateOpts(*mut QemuOpts);
+
+impl CreateOpts {
+    pub fn from_raw(opts: *mut QemuOpts) -> CreateOpts
+    {
+        CreateOpts(opts)
+    }
+
+    pub fn get_size(&self, name: &str, default: u64) -> u64
+    {
+        let name = CString::new(name).unwrap();
+        unsafe { functions::qemu_opt_get_size(self.0, name.as_ptr() as *const u8, default) }
+    }
+
+    pub fn get_bool(&self, name: &str, default: bool) -> bool
+    {
+        let name = CString::new(name).unwrap();
+        unsafe { functions::qemu_opt_get_bool(self.0, name.as_ptr() as *const u8, default) }
+    }
+
+    pub fn get_string(&self, name: &str) -> Option<String>
+    {
+        let name = CString::new(name).unwrap();
+        let value = unsafe {
+            functions::qemu_opt_get(self.0, name.as_ptr() as *const u8)
+        };
+
+        if value.is_null() {
+            None
+        } else {
+            let value = unsafe { CStr::from_ptr(value as *const i8) };
+            Some(value.to_string_lossy().into_owned())
+        }
+    }
+}
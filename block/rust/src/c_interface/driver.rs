@@ -0,0 +1,631 @@
+use libc::{c_int,c_void,ENOTSUP};
+use std::ffi::{CStr,CString};
+use std::ptr;
+use std::slice;
+
+use c_interface::{BDRV_O_RDWR,BdrvCheckResult,BdrvChild,BdrvChildRole,
+                  BlockDriver,BlockDriverInfo,BlockDriverState,CreateOpts,
+                  Error,QDict,QEMUIOVector,QemuOpts,QemuOptDesc,bdrv_register,
+                  error_setg};
+use c_interface::functions;
+
+
+/* Flags for BlockDriverOps::co_get_block_status()'s return value, matching
+ * the BDRV_BLOCK_* constants in block.h. */
+pub const BDRV_BLOCK_DATA: i64 = 0x01;
+pub const BDRV_BLOCK_ZERO: i64 = 0x02;
+
+
+/// Everything a `BlockDriverOps` method can fail with: the errno to hand
+/// back through the vtable, plus a message forwarded to the C side's
+/// `errp` (if the entry point the trampoline is backing takes one).
+#[derive(Debug)]
+pub struct BlockError {
+    pub errno: c_int,
+    pub message: String,
+}
+
+impl BlockError {
+    pub fn new(errno: c_int, message: String) -> BlockError
+    {
+        BlockError { errno: errno, message: message }
+    }
+
+    pub fn unsupported() -> BlockError
+    {
+        BlockError::new(-ENOTSUP, String::from("Operation not supported"))
+    }
+
+    unsafe fn report(&self, errp: *mut *mut Error)
+    {
+        if !errp.is_null() {
+            error_setg(errp, self.message.clone());
+        }
+    }
+}
+
+pub type BlockResult<T> = Result<T, BlockError>;
+
+
+/// Safe handle to a `BlockDriverState`, passed to `BlockDriverOps` methods
+/// instead of the raw pointer C hands the trampolines.
+pub struct Bs(*mut BlockDriverState);
+
+impl Bs {
+    /// Recover the driver's own state out of `bs->opaque`. The returned
+    /// reference is deliberately not tied to `&self`'s borrow (`self.0`
+    /// is just a pointer, copied out before we touch the heap): callers
+    /// need to pass `&mut self` to the driver method alongside this, and
+    /// those are two independent borrows of the same C object, not of
+    /// each other.
+    unsafe fn opaque<'a, T>(&self) -> &'a mut T
+    {
+        &mut *((*self.0).opaque as *mut T)
+    }
+
+    /// Read `buf.len()` bytes at `offset` from the underlying protocol
+    /// child (`bs->file`), as opened by the generic block layer before
+    /// this driver's `open()` ran.
+    pub fn read_from_file(&self, offset: i64, buf: &mut [u8]) -> BlockResult<()>
+    {
+        let ret = unsafe {
+            functions::bdrv_pread((*self.0).file, offset,
+                                  buf.as_mut_ptr() as *mut c_void,
+                                  buf.len() as c_int)
+        };
+
+        if ret < 0 {
+            Err(BlockError::new(ret, String::from("Failed to read from file")))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The underlying protocol/data child (`bs->file`), as opened by the
+    /// generic block layer before this driver's `open()` ran.
+    pub fn file(&self) -> Child
+    {
+        Child(unsafe { (*self.0).file })
+    }
+
+    /// The backing file child, if one has been opened via
+    /// `open_backing_child()`.
+    pub fn backing(&self) -> Option<Child>
+    {
+        let child = unsafe { (*self.0).backing };
+        if child.is_null() {
+            None
+        } else {
+            Some(Child(child))
+        }
+    }
+
+    /// Open `filename` as this node's backing file child. Only makes
+    /// sense for drivers with `BlockDriverOps::SUPPORTS_BACKING` set.
+    pub fn open_backing_child(&mut self, filename: &str) -> BlockResult<Child>
+    {
+        let mut filename = filename.as_bytes().to_vec();
+        filename.push(0);
+
+        let mut errp: *mut Error = ptr::null_mut();
+        let child = unsafe {
+            functions::bdrv_open_child(filename.as_ptr(), ptr::null_mut(),
+                                       b"backing\0".as_ptr(), self.0,
+                                       &functions::child_backing, true,
+                                       &mut errp)
+        };
+
+        if child.is_null() {
+            Err(BlockError::new(-ENOTSUP,
+                                String::from("Failed to open backing file")))
+        } else {
+            unsafe { (*self.0).backing = child; }
+            Ok(Child(child))
+        }
+    }
+
+    /// Write `buf` at `offset` directly to the underlying protocol child
+    /// (`bs->file`). Used for metadata repair (e.g. `bdrv_check`'s
+    /// `fix`), bypassing whatever the driver itself reports through
+    /// `bdrv_co_pwritev`.
+    pub fn write_to_file(&mut self, offset: i64, buf: &[u8]) -> BlockResult<()>
+    {
+        let ret = unsafe {
+            functions::bdrv_child_pwrite((*self.0).file, offset,
+                                        buf.as_ptr() as *const c_void,
+                                        buf.len() as c_int)
+        };
+
+        if ret < 0 {
+            Err(BlockError::new(ret, String::from("Failed to write to file")))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+
+/// Safe handle to a `BdrvChild`: another `BlockDriverState` this node
+/// reads from or writes to, such as the protocol file or a backing image.
+pub struct Child(*mut BdrvChild);
+
+impl Child {
+    pub fn read(&self, offset: i64, buf: &mut [u8]) -> BlockResult<()>
+    {
+        let ret = unsafe {
+            functions::bdrv_pread(self.0, offset, buf.as_mut_ptr() as *mut c_void,
+                                  buf.len() as c_int)
+        };
+
+        if ret < 0 {
+            Err(BlockError::new(ret, String::from("Failed to read from child")))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether this child's image is guaranteed to read back as zeroes
+    /// where nothing has been written yet.
+    pub fn has_zero_init(&self) -> bool
+    {
+        unsafe { functions::bdrv_has_zero_init((*self.0).bs) != 0 }
+    }
+
+    /// Current length, in bytes, of this child's image.
+    pub fn length(&self) -> BlockResult<i64>
+    {
+        let len = unsafe { functions::bdrv_getlength((*self.0).bs) };
+
+        if len < 0 {
+            Err(BlockError::new(len as c_int, String::from("Failed to query length")))
+        } else {
+            Ok(len)
+        }
+    }
+}
+
+
+/// A freshly created, directly opened image file, for `BlockDriverOps`'s
+/// `create()` to lay out its metadata into. There is no `BlockDriverState`
+/// of the driver's own type yet at creation time, so this bypasses the
+/// vtable entirely and talks to the protocol/file layer straight away.
+pub struct RawFile(*mut BlockDriverState);
+
+impl RawFile {
+    /// Create `filename` (via the generic protocol layer) and open it
+    /// for writing.
+    pub fn create(filename: &str) -> BlockResult<RawFile>
+    {
+        let cfilename = CString::new(filename).unwrap();
+        let mut errp: *mut Error = ptr::null_mut();
+
+        let ret = unsafe {
+            functions::bdrv_create_file(cfilename.as_ptr() as *const u8,
+                                        ptr::null_mut(), &mut errp)
+        };
+        if ret < 0 {
+            return Err(BlockError::new(ret,
+                format!("Failed to create '{}'", filename)));
+        }
+
+        let mut bs: *mut BlockDriverState = ptr::null_mut();
+        let ret = unsafe {
+            functions::bdrv_open(&mut bs, cfilename.as_ptr() as *const u8,
+                                 ptr::null(), ptr::null_mut(), BDRV_O_RDWR,
+                                 &mut errp)
+        };
+        if ret < 0 {
+            return Err(BlockError::new(ret,
+                format!("Failed to open '{}'", filename)));
+        }
+
+        Ok(RawFile(bs))
+    }
+
+    pub fn write(&mut self, offset: i64, buf: &[u8]) -> BlockResult<()>
+    {
+        let ret = unsafe {
+            functions::bdrv_pwrite(self.0, offset, buf.as_ptr() as *const c_void,
+                                   buf.len() as c_int)
+        };
+
+        if ret < 0 {
+            Err(BlockError::new(ret, String::from("Failed to write to file")))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for RawFile {
+    fn drop(&mut self)
+    {
+        unsafe { functions::bdrv_unref(self.0); }
+    }
+}
+
+
+/// What `BlockDriverOps::co_get_block_status()` reports about a run of
+/// sectors starting at the requested one.
+pub struct BlockStatus {
+    /// Number of sectors (starting at the one that was asked about) this
+    /// status applies to; must be <= the number of sectors requested.
+    pub pnum: c_int,
+    /// The range contains actual data (as opposed to being unallocated).
+    pub data: bool,
+    /// The range reads as zeroes (set together with `data` for
+    /// allocated zero clusters, or alone for unallocated ones).
+    pub zero: bool,
+}
+
+
+/// Safe view of a `QEMUIOVector`: lets a driver move bytes in and out of
+/// guest memory without touching the raw iovecs itself.
+pub struct IoVector<'a>(&'a mut QEMUIOVector);
+
+impl<'a> IoVector<'a> {
+    pub fn len(&self) -> usize
+    {
+        self.0.size as usize
+    }
+
+    /// Copy `self.len()` bytes out of the vector and into `buf`.
+    pub fn copy_to(&self, buf: &mut [u8])
+    {
+        assert!(buf.len() >= self.len());
+
+        let mut pos = 0;
+        for i in 0..self.0.niov as isize {
+            unsafe {
+                let iov = &*self.0.iov.offset(i);
+                let src = slice::from_raw_parts(iov.iov_base as *const u8,
+                                                iov.iov_len as usize);
+                buf[pos..(pos + src.len())].copy_from_slice(src);
+                pos += src.len();
+            }
+        }
+    }
+
+    /// Copy bytes from `buf` into the vector, filling at most `self.len()`.
+    pub fn copy_from(&mut self, buf: &[u8])
+    {
+        assert!(buf.len() >= self.len());
+
+        let mut pos = 0;
+        for i in 0..self.0.niov as isize {
+            unsafe {
+                let iov = &*self.0.iov.offset(i);
+                let dst = slice::from_raw_parts_mut(iov.iov_base as *mut u8,
+                                                    iov.iov_len as usize);
+                dst.copy_from_slice(&buf[pos..(pos + dst.len())]);
+                pos += dst.len();
+            }
+        }
+    }
+}
+
+
+/// Safe equivalent of the `BlockDriver` vtable. Implement whichever
+/// methods your driver supports, then call `register::<Self>(name)`
+/// once to generate the `extern fn` trampolines and populate the real
+/// C vtable.
+pub trait BlockDriverOps: Sized {
+    /// Whether this is a format driver that understands a backing file
+    /// reference of its own (as opposed to e.g. a raw passthrough).
+    const SUPPORTS_BACKING: bool = false;
+
+    fn open(bs: &mut Bs, options: &mut QDict, flags: c_int)
+        -> BlockResult<Self>;
+
+    fn close(&mut self, _bs: &mut Bs)
+    {
+    }
+
+    fn co_preadv(&mut self, _bs: &mut Bs, _offset: u64, _iov: &mut IoVector)
+        -> BlockResult<()>
+    {
+        Err(BlockError::unsupported())
+    }
+
+    fn co_pwritev(&mut self, _bs: &mut Bs, _offset: u64, _iov: &mut IoVector)
+        -> BlockResult<()>
+    {
+        Err(BlockError::unsupported())
+    }
+
+    fn co_flush(&mut self, _bs: &mut Bs) -> BlockResult<()>
+    {
+        Ok(())
+    }
+
+    fn get_info(&mut self, _bs: &mut Bs) -> BlockResult<BlockDriverInfo>
+    {
+        Err(BlockError::unsupported())
+    }
+
+    fn truncate(&mut self, _bs: &mut Bs, _offset: i64) -> BlockResult<()>
+    {
+        Err(BlockError::unsupported())
+    }
+
+    fn co_get_block_status(&mut self, _bs: &mut Bs, _sector_num: i64,
+                           nb_sectors: c_int)
+        -> BlockResult<BlockStatus>
+    {
+        /* Conservatively: the whole range is allocated data. */
+        Ok(BlockStatus { pnum: nb_sectors, data: true, zero: false })
+    }
+
+    /// Option descriptions for `qemu-img create -f <this driver>`.
+    /// An empty list (the default) means the driver can't create images.
+    fn create_opts() -> Vec<QemuOptDesc>
+    {
+        Vec::new()
+    }
+
+    /// Create a new image at `filename` according to `opts` (whose
+    /// contents match `create_opts()`). There is no live instance of
+    /// `Self` yet; implementations write the image out through
+    /// `RawFile`.
+    fn create(_filename: &str, _opts: &CreateOpts) -> BlockResult<()>
+    {
+        Err(BlockError::unsupported())
+    }
+
+    /// Check the image's metadata for corruptions and leaks, filling in
+    /// the result; if `fix` is set, repair whatever can be repaired
+    /// in-place.
+    fn check(&mut self, _bs: &mut Bs, _fix: bool) -> BlockResult<BdrvCheckResult>
+    {
+        Err(BlockError::unsupported())
+    }
+}
+
+
+extern fn open_trampoline<T: BlockDriverOps>(bs: *mut BlockDriverState,
+                                             options: *mut QDict,
+                                             flags: c_int,
+                                             errp: *mut *mut Error)
+    -> c_int
+{
+    let mut safe_bs = Bs(bs);
+    let options = unsafe { &mut *options };
+
+    match T::open(&mut safe_bs, options, flags) {
+        Ok(driver) => {
+            unsafe {
+                (*bs).opaque = Box::into_raw(Box::new(driver)) as *mut c_void;
+            }
+            0
+        }
+
+        Err(e) => {
+            unsafe { e.report(errp); }
+            e.errno
+        }
+    }
+}
+
+extern fn close_trampoline<T: BlockDriverOps>(bs: *mut BlockDriverState)
+{
+    let mut safe_bs = Bs(bs);
+
+    unsafe {
+        let mut driver = Box::from_raw((*bs).opaque as *mut T);
+        driver.close(&mut safe_bs);
+    }
+}
+
+extern fn co_preadv_trampoline<T: BlockDriverOps>(bs: *mut BlockDriverState,
+                                                  offset: u64, _bytes: u64,
+                                                  qiov: *mut QEMUIOVector,
+                                                  _flags: c_int)
+    -> c_int
+{
+    let mut safe_bs = Bs(bs);
+    let driver: &mut T = unsafe { safe_bs.opaque() };
+    let mut iov = IoVector(unsafe { &mut *qiov });
+
+    match driver.co_preadv(&mut safe_bs, offset, &mut iov) {
+        Ok(()) => 0,
+        Err(e) => e.errno,
+    }
+}
+
+extern fn co_pwritev_trampoline<T: BlockDriverOps>(bs: *mut BlockDriverState,
+                                                   offset: u64, _bytes: u64,
+                                                   qiov: *mut QEMUIOVector,
+                                                   _flags: c_int)
+    -> c_int
+{
+    let mut safe_bs = Bs(bs);
+    let driver: &mut T = unsafe { safe_bs.opaque() };
+    let mut iov = IoVector(unsafe { &mut *qiov });
+
+    match driver.co_pwritev(&mut safe_bs, offset, &mut iov) {
+        Ok(()) => 0,
+        Err(e) => e.errno,
+    }
+}
+
+extern fn co_flush_trampoline<T: BlockDriverOps>(bs: *mut BlockDriverState)
+    -> c_int
+{
+    let mut safe_bs = Bs(bs);
+    let driver: &mut T = unsafe { safe_bs.opaque() };
+
+    match driver.co_flush(&mut safe_bs) {
+        Ok(()) => 0,
+        Err(e) => e.errno,
+    }
+}
+
+extern fn get_info_trampoline<T: BlockDriverOps>(bs: *mut BlockDriverState,
+                                                 bdi: *mut BlockDriverInfo)
+    -> c_int
+{
+    let mut safe_bs = Bs(bs);
+    let driver: &mut T = unsafe { safe_bs.opaque() };
+
+    match driver.get_info(&mut safe_bs) {
+        Ok(info) => {
+            unsafe { *bdi = info; }
+            0
+        }
+
+        Err(e) => e.errno,
+    }
+}
+
+extern fn truncate_trampoline<T: BlockDriverOps>(bs: *mut BlockDriverState,
+                                                 offset: i64,
+                                                 errp: *mut *mut Error)
+    -> c_int
+{
+    let mut safe_bs = Bs(bs);
+    let driver: &mut T = unsafe { safe_bs.opaque() };
+
+    match driver.truncate(&mut safe_bs, offset) {
+        Ok(()) => 0,
+
+        Err(e) => {
+            unsafe { e.report(errp); }
+            e.errno
+        }
+    }
+}
+
+
+extern fn co_get_block_status_trampoline<T: BlockDriverOps>(
+        bs: *mut BlockDriverState, sector_num: i64, nb_sectors: c_int,
+        pnum: *mut c_int, file: *mut *mut BlockDriverState)
+    -> i64
+{
+    let mut safe_bs = Bs(bs);
+    let driver: &mut T = unsafe { safe_bs.opaque() };
+
+    match driver.co_get_block_status(&mut safe_bs, sector_num, nb_sectors) {
+        Ok(status) => {
+            let mut ret = 0i64;
+
+            unsafe { *pnum = status.pnum; }
+
+            if status.data {
+                ret |= BDRV_BLOCK_DATA;
+            }
+            if status.zero {
+                ret |= BDRV_BLOCK_ZERO;
+            }
+            if status.data && !file.is_null() {
+                unsafe { *file = bs; }
+            }
+
+            ret
+        }
+
+        Err(e) => e.errno as i64,
+    }
+}
+
+
+extern fn has_zero_init_trampoline<T: BlockDriverOps>(bs: *mut BlockDriverState)
+    -> c_int
+{
+    let file = Bs(bs).file();
+
+    /* True only if the child we actually store our data in guarantees
+     * it; a format driver has no say in this itself. */
+    if file.0.is_null() {
+        0
+    } else {
+        file.has_zero_init() as c_int
+    }
+}
+
+/* Transparent pass-through: a format driver wrapping a single data child
+ * needs (at least) whatever permissions its parent asked for, and is
+ * happy to share whatever its parent is happy to share. */
+extern fn child_perm_trampoline<T: BlockDriverOps>(
+        _bs: *mut BlockDriverState, _c: *mut BdrvChild,
+        _role: *const BdrvChildRole, parent_perm: u64, parent_shared: u64,
+        nperm: *mut u64, nshared: *mut u64)
+{
+    unsafe {
+        *nperm = parent_perm;
+        *nshared = parent_shared;
+    }
+}
+
+
+extern fn create_trampoline<T: BlockDriverOps>(filename: *const u8,
+                                               opts: *mut QemuOpts,
+                                               errp: *mut *mut Error)
+    -> c_int
+{
+    let filename = unsafe { CStr::from_ptr(filename as *const i8) }
+        .to_string_lossy().into_owned();
+    let opts = CreateOpts::from_raw(opts);
+
+    match T::create(&filename, &opts) {
+        Ok(()) => 0,
+
+        Err(e) => {
+            unsafe { e.report(errp); }
+            e.errno
+        }
+    }
+}
+
+
+extern fn check_trampoline<T: BlockDriverOps>(bs: *mut BlockDriverState,
+                                              result: *mut BdrvCheckResult,
+                                              fix: c_int)
+    -> c_int
+{
+    let mut safe_bs = Bs(bs);
+    let driver: &mut T = unsafe { safe_bs.opaque() };
+
+    match driver.check(&mut safe_bs, fix != 0) {
+        Ok(res) => {
+            unsafe { *result = res; }
+            0
+        }
+
+        Err(e) => e.errno,
+    }
+}
+
+
+/// Build a `BlockDriver` vtable out of trampolines for `T`'s
+/// `BlockDriverOps` implementation and register it with the C block
+/// layer under `name` (which must be a `'\0'`-terminated string, as
+/// `BlockDriver::new()` expects).
+pub fn register<T: BlockDriverOps>(name: &'static str)
+{
+    let mut bdrv = BlockDriver::new(name, 0);
+
+    bdrv.supports_backing = T::SUPPORTS_BACKING;
+
+    bdrv.bdrv_open = Some(open_trampoline::<T>);
+    bdrv.bdrv_close = Some(close_trampoline::<T>);
+    bdrv.bdrv_co_preadv = Some(co_preadv_trampoline::<T>);
+    bdrv.bdrv_co_pwritev = Some(co_pwritev_trampoline::<T>);
+    bdrv.bdrv_co_flush = Some(co_flush_trampoline::<T>);
+    bdrv.bdrv_get_info = Some(get_info_trampoline::<T>);
+    bdrv.bdrv_truncate = Some(truncate_trampoline::<T>);
+    bdrv.bdrv_co_get_block_status = Some(co_get_block_status_trampoline::<T>);
+    bdrv.bdrv_has_zero_init = Some(has_zero_init_trampoline::<T>);
+    bdrv.bdrv_child_perm = Some(child_perm_trampoline::<T>);
+    bdrv.bdrv_create = Some(create_trampoline::<T>);
+    bdrv.bdrv_check = Some(check_trampoline::<T>);
+
+    let create_opts = T::create_opts();
+    if !create_opts.is_empty() {
+        bdrv.create_opts = unsafe {
+            functions::rust_make_opts_list(name.as_ptr(), create_opts.as_ptr(),
+                                           create_opts.len())
+        };
+    }
+
+    bdrv_register(bdrv);
+}
@@ -1,5 +1,5 @@
 use c_interface::*;
-use libc::c_int;
+use libc::{c_int,c_void,size_t};
 
 
 extern {
@@ -7,4 +7,65 @@ extern {
     pub fn error_setg_internal(errp: *mut *mut Error, src: *const u8,
                                line: c_int, func: *const u8,
                                fmt: *const u8, ...);
+
+    /* Synchronous read on a BdrvChild; internally yields to the block
+     * layer's coroutine-based bdrv_co_preadv(). */
+    pub fn bdrv_pread(child: *mut BdrvChild, offset: i64, buf: *mut c_void,
+                      bytes: c_int)
+        -> c_int;
+
+    /* Let the generic block layer open (and recursively probe) another
+     * image as a child of `parent`, instead of the driver doing its own
+     * file handling. */
+    pub fn bdrv_open_child(filename: *const u8, options: *mut QDict,
+                           bdref_key: *const u8, parent: *mut BlockDriverState,
+                           child_role: *const BdrvChildRole,
+                           allow_none: bool, errp: *mut *mut Error)
+        -> *mut BdrvChild;
+
+    pub fn bdrv_has_zero_init(bs: *mut BlockDriverState) -> c_int;
+    pub fn bdrv_getlength(bs: *mut BlockDriverState) -> i64;
+
+    /* Synchronous write on a BdrvChild; used by bdrv_check() to repair
+     * refcount metadata directly, even on a driver like qcow2-rust that
+     * doesn't support writes at the guest I/O level yet. */
+    pub fn bdrv_child_pwrite(child: *mut BdrvChild, offset: i64,
+                             buf: *const c_void, bytes: c_int)
+        -> c_int;
+
+    /* Child roles for bdrv_open_child(): a plain protocol/file child, a
+     * format driver's data child, and a backing file. */
+    pub static child_file: BdrvChildRole;
+    pub static child_format: BdrvChildRole;
+    pub static child_backing: BdrvChildRole;
+
+    /* Creating an image: make the protocol-level file, then open it
+     * directly (bypassing any format probing) to write the metadata a
+     * format driver's bdrv_create() wants to lay out. */
+    pub fn bdrv_create_file(filename: *const u8, opts: *mut QemuOpts,
+                            errp: *mut *mut Error)
+        -> c_int;
+    pub fn bdrv_open(pbs: *mut *mut BlockDriverState, filename: *const u8,
+                     reference: *const u8, options: *mut QDict, flags: c_int,
+                     errp: *mut *mut Error)
+        -> c_int;
+    pub fn bdrv_pwrite(bs: *mut BlockDriverState, offset: i64,
+                       buf: *const c_void, bytes: c_int)
+        -> c_int;
+    pub fn bdrv_unref(bs: *mut BlockDriverState);
+
+    /* Accessors for the QemuOpts a bdrv_create() implementation is handed. */
+    pub fn qemu_opt_get(opts: *mut QemuOpts, name: *const u8) -> *const u8;
+    pub fn qemu_opt_get_bool(opts: *mut QemuOpts, name: *const u8,
+                             defval: bool)
+        -> bool;
+    pub fn qemu_opt_get_size(opts: *mut QemuOpts, name: *const u8,
+                             defval: u64)
+        -> u64;
+
+    /* Build a QemuOptsList (opaque to Rust) out of a plain array of
+     * option descriptions, for BlockDriver::create_opts. */
+    pub fn rust_make_opts_list(name: *const u8, desc: *const QemuOptDesc,
+                               count: size_t)
+        -> *mut QemuOptsList;
 }
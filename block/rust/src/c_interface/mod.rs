@@ -2,6 +2,16 @@ use libc::{c_int,c_ulong,c_void,size_t};
 use std::ptr;
 
 mod functions;
+mod driver;
+mod create;
+
+pub use self::driver::{BDRV_BLOCK_DATA,BDRV_BLOCK_ZERO,BlockDriverOps,
+                       BlockError,BlockResult,BlockStatus,Bs,Child,IoVector,
+                       RawFile,register};
+pub use self::create::{CreateOpts,QemuOptDesc,QemuOptType};
+
+/* Flags for bdrv_open(); matches the BDRV_O_* constants in block.h. */
+pub const BDRV_O_RDWR: c_int = 0x0002;
 
 
 #[repr(C)]
@@ -314,6 +324,9 @@ pub struct BlockDriverState {
     pub opaque: *mut c_void,
 
     pub aio_context: *mut AioContext,
+
+    pub file: *mut BdrvChild,
+    pub backing: *mut BdrvChild,
 }
 
 #[repr(C)]
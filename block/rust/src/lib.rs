@@ -0,0 +1,5 @@
+extern crate libc;
+extern crate flate2;
+
+mod c_interface;
+mod qcow2;
@@ -0,0 +1,114 @@
+use std::collections::{HashMap,VecDeque};
+
+
+/// A small LRU cache of decoded L2 tables, keyed by the table's host
+/// cluster offset, so a run of requests hitting the same region of the
+/// image doesn't re-read its L2 table from disk every time.
+pub struct L2Cache {
+    capacity: usize,
+    tables: HashMap<u64, Vec<u64>>,
+    /* Most recently used offset is at the back. */
+    lru: VecDeque<u64>,
+}
+
+impl L2Cache {
+    pub fn new(capacity: usize) -> L2Cache
+    {
+        L2Cache {
+            capacity: capacity,
+            tables: HashMap::new(),
+            lru: VecDeque::new(),
+        }
+    }
+
+    pub fn get(&mut self, offset: u64) -> Option<&Vec<u64>>
+    {
+        if self.tables.contains_key(&offset) {
+            self.touch(offset);
+            self.tables.get(&offset)
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(&mut self, offset: u64, table: Vec<u64>)
+    {
+        if !self.tables.contains_key(&offset) && self.tables.len() >= self.capacity {
+            if let Some(evict) = self.lru.pop_front() {
+                self.tables.remove(&evict);
+            }
+        }
+
+        self.tables.insert(offset, table);
+        self.touch(offset);
+    }
+
+    fn touch(&mut self, offset: u64)
+    {
+        self.lru.retain(|&o| o != offset);
+        self.lru.push_back(offset);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_miss_on_empty_cache()
+    {
+        let mut cache = L2Cache::new(2);
+        assert!(cache.get(0x1000).is_none());
+    }
+
+    #[test]
+    fn insert_then_get_hits()
+    {
+        let mut cache = L2Cache::new(2);
+        cache.insert(0x1000, vec![1, 2, 3]);
+        assert_eq!(cache.get(0x1000), Some(&vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn insert_evicts_least_recently_used()
+    {
+        let mut cache = L2Cache::new(2);
+        cache.insert(0x1000, vec![1]);
+        cache.insert(0x2000, vec![2]);
+        cache.insert(0x3000, vec![3]);
+
+        /* 0x1000 was the least recently used and should have been evicted
+         * to make room for 0x3000. */
+        assert!(cache.get(0x1000).is_none());
+        assert_eq!(cache.get(0x2000), Some(&vec![2]));
+        assert_eq!(cache.get(0x3000), Some(&vec![3]));
+    }
+
+    #[test]
+    fn get_refreshes_recency()
+    {
+        let mut cache = L2Cache::new(2);
+        cache.insert(0x1000, vec![1]);
+        cache.insert(0x2000, vec![2]);
+
+        /* Touch 0x1000 so it's no longer the least recently used. */
+        cache.get(0x1000);
+        cache.insert(0x3000, vec![3]);
+
+        assert_eq!(cache.get(0x1000), Some(&vec![1]));
+        assert!(cache.get(0x2000).is_none());
+    }
+
+    #[test]
+    fn reinserting_existing_key_does_not_evict()
+    {
+        let mut cache = L2Cache::new(2);
+        cache.insert(0x1000, vec![1]);
+        cache.insert(0x2000, vec![2]);
+        cache.insert(0x1000, vec![99]);
+
+        assert_eq!(cache.get(0x1000), Some(&vec![99]));
+        assert_eq!(cache.get(0x2000), Some(&vec![2]));
+    }
+}
@@ -0,0 +1,213 @@
+use std::cmp;
+
+use libc::ENOTSUP;
+
+use c_interface::{BdrvCheckResult,BlockError,BlockResult,Bs};
+
+use qcow2::format::{self,Cluster,Header};
+
+
+/// Account one more reference to the cluster containing `host_offset`,
+/// tracking the highest cluster end seen so far in `result.image_end_offset`.
+/// Offsets that aren't cluster-aligned, or that point past the end of the
+/// image, are flagged as `check_errors` instead (there's no refcount slot
+/// to credit them to).
+fn account_cluster(refcounts: &mut [u32], cluster_bits: u32, host_offset: u64,
+                   result: &mut BdrvCheckResult)
+{
+    if host_offset & ((1u64 << cluster_bits) - 1) != 0 {
+        result.check_errors += 1;
+        return;
+    }
+
+    let cluster = (host_offset >> cluster_bits) as usize;
+    if cluster >= refcounts.len() {
+        result.check_errors += 1;
+        return;
+    }
+
+    refcounts[cluster] += 1;
+
+    let end = host_offset + (1u64 << cluster_bits);
+    if end as i64 > result.image_end_offset {
+        result.image_end_offset = end as i64;
+    }
+}
+
+/// Walk the image's metadata (header, refcount table/blocks, L1/L2 tables
+/// and the data clusters they point to) to build the refcounts the image
+/// should have, then compare that against what's actually stored on disk.
+///
+/// This doesn't walk internal snapshots' own L1 tables yet, so it refuses
+/// to run at all on an image that has any: a data cluster referenced only
+/// by a snapshot would otherwise look unreferenced here (expected == 0),
+/// which would misreport it as a leak and, with `fix`, actually free it
+/// while it's still live in the snapshot.
+pub fn check(header: &Header, l1_table: &[u64], bs: &mut Bs, fix: bool)
+    -> BlockResult<BdrvCheckResult>
+{
+    if header.nb_snapshots != 0 {
+        return Err(BlockError::new(-ENOTSUP,
+            String::from("qcow2-rust cannot check images with internal \
+                          snapshots yet")));
+    }
+
+    let cluster_size = header.cluster_size();
+    let cluster_bits = header.cluster_bits;
+
+    let file_len = bs.file().length()?;
+    let total_clusters = (file_len as u64).div_ceil(cluster_size);
+
+    let mut result = BdrvCheckResult {
+        corruptions: 0,
+        leaks: 0,
+        check_errors: 0,
+        corruptions_fixed: 0,
+        leaks_fixed: 0,
+        image_end_offset: 0,
+        bfi: 0,
+    };
+
+    let mut refcounts = vec![0u32; total_clusters as usize];
+
+    /* The header always occupies cluster 0. */
+    account_cluster(&mut refcounts, cluster_bits, 0, &mut result);
+
+    /* The refcount table itself, and the refcount blocks it points to. */
+    let refcount_table_bytes = header.refcount_table_clusters as u64 * cluster_size;
+    let mut refcount_table_raw = vec![0u8; refcount_table_bytes as usize];
+    bs.read_from_file(header.refcount_table_offset as i64, &mut refcount_table_raw)?;
+
+    for i in 0..(header.refcount_table_clusters as u64) {
+        account_cluster(&mut refcounts, cluster_bits,
+                        header.refcount_table_offset + i * cluster_size, &mut result);
+    }
+
+    let refcount_table_entries = refcount_table_bytes / 8;
+    let mut refcount_block_offsets = Vec::with_capacity(refcount_table_entries as usize);
+    for i in 0..refcount_table_entries {
+        let entry = format::read_be64(&refcount_table_raw, (i * 8) as usize);
+        refcount_block_offsets.push(entry);
+        if entry != 0 {
+            account_cluster(&mut refcounts, cluster_bits, entry, &mut result);
+        }
+    }
+
+    /* The L1 table itself. */
+    let l1_bytes = l1_table.len() as u64 * 8;
+    let l1_clusters = l1_bytes.div_ceil(cluster_size);
+    for i in 0..l1_clusters {
+        account_cluster(&mut refcounts, cluster_bits,
+                        header.l1_table_offset + i * cluster_size, &mut result);
+    }
+
+    /* L1 -> L2 -> data. */
+    for &l1_entry in l1_table {
+        if format::l1_entry_has_reserved_bits(l1_entry) {
+            result.check_errors += 1;
+        }
+
+        let l2_offset = format::l1_entry_l2_offset(l1_entry);
+        if l2_offset == 0 {
+            continue;
+        }
+
+        account_cluster(&mut refcounts, cluster_bits, l2_offset, &mut result);
+
+        let mut l2_raw = vec![0u8; cluster_size as usize];
+        bs.read_from_file(l2_offset as i64, &mut l2_raw)?;
+
+        for i in 0..header.l2_entries() {
+            let entry = format::read_be64(&l2_raw, (i * 8) as usize);
+            if entry == 0 {
+                continue;
+            }
+
+            match format::decode_l2_entry(header, entry)? {
+                Some(Cluster::Plain { host_offset }) => {
+                    if format::l2_entry_has_reserved_bits(entry) {
+                        result.check_errors += 1;
+                    }
+                    account_cluster(&mut refcounts, cluster_bits, host_offset, &mut result);
+                }
+
+                Some(Cluster::Zero { host_offset }) => {
+                    if format::l2_entry_has_reserved_bits(entry) {
+                        result.check_errors += 1;
+                    }
+                    /* A sparse zero cluster (host_offset == 0) doesn't
+                     * reference any host cluster at all. */
+                    if host_offset != 0 {
+                        account_cluster(&mut refcounts, cluster_bits, host_offset, &mut result);
+                    }
+                }
+
+                Some(Cluster::Compressed { file_offset, size, header_skip }) => {
+                    let first_cluster = file_offset >> cluster_bits;
+                    let last_byte = file_offset + header_skip as u64 + size as u64 - 1;
+                    let last_cluster = last_byte >> cluster_bits;
+
+                    for cluster in first_cluster..(last_cluster + 1) {
+                        account_cluster(&mut refcounts, cluster_bits,
+                                        cluster << cluster_bits, &mut result);
+                    }
+                }
+
+                None => {}
+            }
+        }
+    }
+
+    /* Compare the expected refcounts against what's on disk, rewriting
+     * the refcount blocks to match if `fix` was requested. */
+    let refcount_entries_per_block = cluster_size / format::REFCOUNT_BYTES;
+
+    for (block_index, &block_offset) in refcount_block_offsets.iter().enumerate() {
+        let base_cluster = block_index as u64 * refcount_entries_per_block;
+        if base_cluster >= total_clusters {
+            break;
+        }
+
+        let entries_here = cmp::min(refcount_entries_per_block,
+                                    total_clusters - base_cluster);
+
+        /* A refcount-table entry of 0 means there's no refcount block
+         * allocated for this range at all; we don't allocate one here,
+         * so there's nothing to write a fix to even if `fix` is set. */
+        let can_write = block_offset != 0;
+
+        let mut block_raw = vec![0u8; cluster_size as usize];
+        if can_write {
+            bs.read_from_file(block_offset as i64, &mut block_raw)?;
+        }
+
+        let mut dirty = false;
+        for i in 0..entries_here {
+            let cluster = (base_cluster + i) as usize;
+            let on_disk = format::read_be16(&block_raw, (i * 2) as usize) as u32;
+            let expected = refcounts[cluster];
+
+            if on_disk < expected {
+                result.corruptions += 1;
+                if fix && can_write {
+                    format::write_be16(&mut block_raw, (i * 2) as usize, expected as u16);
+                    result.corruptions_fixed += 1;
+                    dirty = true;
+                }
+            } else if on_disk > expected {
+                result.leaks += 1;
+                if fix && can_write {
+                    format::write_be16(&mut block_raw, (i * 2) as usize, expected as u16);
+                    result.leaks_fixed += 1;
+                    dirty = true;
+                }
+            }
+        }
+
+        if dirty {
+            bs.write_to_file(block_offset as i64, &block_raw)?;
+        }
+    }
+
+    Ok(result)
+}
@@ -0,0 +1,149 @@
+use libc::{EINVAL,ENOTSUP};
+
+use c_interface::{BlockError,BlockResult,CreateOpts,QemuOptDesc,QemuOptType,
+                  RawFile};
+
+use qcow2::format::{self,MAX_CLUSTER_BITS,MIN_CLUSTER_BITS,QCOW_MAGIC};
+
+
+const DEFAULT_CLUSTER_SIZE: u64 = 65536;
+
+/* How many of the image's leading clusters are metadata laid out by
+ * create() itself, in order: header, L1 table, refcount table, first
+ * (and, for any image this driver can create, only) refcount block. */
+const FIXED_METADATA_CLUSTERS: u64 = 4;
+
+
+pub fn create_opts() -> Vec<QemuOptDesc>
+{
+    vec![
+        QemuOptDesc::new("size", QemuOptType::Size,
+                         "Virtual disk size"),
+        QemuOptDesc::new("cluster_size", QemuOptType::Size,
+                         "qcow2 cluster size"),
+        QemuOptDesc::new("backing_file", QemuOptType::String,
+                         "File name of a base image"),
+        QemuOptDesc::new("compat", QemuOptType::String,
+                         "Compatibility level (0.10 or 1.1)"),
+        QemuOptDesc::new("preallocation", QemuOptType::String,
+                         "Preallocation mode (only 'off' is supported)"),
+    ]
+}
+
+pub fn create(filename: &str, opts: &CreateOpts) -> BlockResult<()>
+{
+    let size = opts.get_size("size", 0);
+    let cluster_size = opts.get_size("cluster_size", DEFAULT_CLUSTER_SIZE);
+    let cluster_bits = cluster_size.trailing_zeros();
+
+    if cluster_size.count_ones() != 1 ||
+        !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits)
+    {
+        return Err(BlockError::new(-EINVAL,
+            String::from("cluster_size must be a power of two between \
+                          512 and 2M")));
+    }
+
+    let compat = opts.get_string("compat")
+        .unwrap_or_else(|| String::from("1.1"));
+    let version = match compat.as_str() {
+        "0.10" => 2,
+        "1.1" => 3,
+        _ => return Err(BlockError::new(-EINVAL,
+                 format!("Unknown compat level '{}' (use 0.10 or 1.1)",
+                         compat))),
+    };
+
+    if let Some(preallocation) = opts.get_string("preallocation") {
+        if preallocation != "off" {
+            return Err(BlockError::new(-ENOTSUP,
+                format!("Unsupported preallocation mode '{}'",
+                        preallocation)));
+        }
+    }
+
+    let backing_file = opts.get_string("backing_file");
+
+    let l2_entries_per_table = cluster_size / 8;
+    let data_clusters = size.div_ceil(cluster_size);
+    let l1_size = data_clusters.div_ceil(l2_entries_per_table) as u32;
+
+    if l1_size as u64 * 8 > cluster_size {
+        return Err(BlockError::new(-ENOTSUP,
+            String::from("Virtual disk size requires a multi-cluster L1 \
+                          table, which qcow2-rust cannot create yet")));
+    }
+
+    /* Cluster layout: 0 = header, 1 = L1 table, 2 = refcount table,
+     * 3 = first refcount block, optionally 4 = backing file name. */
+    let header_cluster = 0u64;
+    let l1_cluster = 1u64;
+    let refcount_table_cluster = 2u64;
+    let refcount_block_cluster = 3u64;
+
+    let (backing_file_offset, backing_file_size, metadata_clusters) =
+        match backing_file {
+            Some(ref name) => {
+                (FIXED_METADATA_CLUSTERS * cluster_size, name.len() as u32,
+                 FIXED_METADATA_CLUSTERS + 1)
+            }
+            None => (0, 0, FIXED_METADATA_CLUSTERS),
+        };
+
+    let refcount_entries_per_block = cluster_size / format::REFCOUNT_BYTES;
+    if metadata_clusters > refcount_entries_per_block {
+        return Err(BlockError::new(-ENOTSUP,
+            String::from("cluster_size too small to hold this image's \
+                          metadata")));
+    }
+
+    let mut file = RawFile::create(filename)?;
+
+    let mut header = vec![0u8; cluster_size as usize];
+    format::write_be32(&mut header, 0, QCOW_MAGIC);
+    format::write_be32(&mut header, 4, version);
+    format::write_be64(&mut header, 8, backing_file_offset);
+    format::write_be32(&mut header, 16, backing_file_size);
+    format::write_be32(&mut header, 20, cluster_bits);
+    format::write_be64(&mut header, 24, size);
+    format::write_be32(&mut header, 32, 0); /* crypt_method: none */
+    format::write_be32(&mut header, 36, l1_size);
+    format::write_be64(&mut header, 40, l1_cluster * cluster_size);
+    format::write_be64(&mut header, 48, refcount_table_cluster * cluster_size);
+    format::write_be32(&mut header, 56, 1); /* refcount_table_clusters */
+    format::write_be32(&mut header, 60, 0); /* nb_snapshots */
+    format::write_be64(&mut header, 64, 0); /* snapshots_offset */
+    if version == 3 {
+        format::write_be64(&mut header, 72, 0); /* incompatible_features */
+        format::write_be64(&mut header, 80, 0); /* compatible_features */
+        format::write_be64(&mut header, 88, 0); /* autoclear_features */
+        format::write_be32(&mut header, 96, 4); /* refcount_order */
+        format::write_be32(&mut header, 100, 104); /* header_length */
+    }
+    file.write((header_cluster * cluster_size) as i64, &header)?;
+
+    let l1_table = vec![0u8; cluster_size as usize];
+    file.write((l1_cluster * cluster_size) as i64, &l1_table)?;
+
+    let mut refcount_table = vec![0u8; cluster_size as usize];
+    format::write_be64(&mut refcount_table, 0,
+                       refcount_block_cluster * cluster_size);
+    file.write((refcount_table_cluster * cluster_size) as i64,
+              &refcount_table)?;
+
+    let mut refcount_block = vec![0u8; cluster_size as usize];
+    for cluster in 0..metadata_clusters {
+        format::write_be16(&mut refcount_block,
+                           (cluster * format::REFCOUNT_BYTES) as usize, 1);
+    }
+    file.write((refcount_block_cluster * cluster_size) as i64,
+              &refcount_block)?;
+
+    if let Some(ref name) = backing_file {
+        let mut name_cluster = vec![0u8; cluster_size as usize];
+        name_cluster[..name.len()].copy_from_slice(name.as_bytes());
+        file.write(backing_file_offset as i64, &name_cluster)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,476 @@
+use libc::EINVAL;
+
+use c_interface::{BlockError,BlockResult};
+
+
+pub const QCOW_MAGIC: u32 = 0x5146_49fb; /* "QFI\xfb" */
+
+/* Top two bits of an L1/L2 entry. */
+const QCOW_OFLAG_COPIED: u64 = 1 << 63;
+const QCOW_OFLAG_COMPRESSED: u64 = 1 << 62;
+
+/* Bit 0 of a standard-cluster L2 entry: the cluster reads as all zeroes
+ * (regardless of any backing file), whether or not host_offset is 0. */
+const QCOW_OFLAG_ZERO: u64 = 1 << 0;
+
+/* Bits 56..61 of an L1 entry, or of a plain (non-compressed) L2 entry,
+ * are reserved and must be zero. */
+const RESERVED_BITS_MASK: u64 = 0x3f00_0000_0000_0000;
+
+/* Host cluster offset stored in a standard (non-compressed) L2 entry:
+ * bits 9..55 (bits 0..8 are reserved/flag bits, not part of the offset). */
+const STD_OFFSET_MASK: u64 = ((1u64 << 56) - 1) & !0x1ffu64;
+
+pub const MIN_CLUSTER_BITS: u32 = 9;
+pub const MAX_CLUSTER_BITS: u32 = 21;
+
+/* qcow2-rust only ever deals with the default refcount width (order 4,
+ * i.e. 16 bits per entry): 2 bytes per refcount entry. */
+pub const REFCOUNT_BYTES: u64 = 2;
+
+
+fn be32(buf: &[u8], off: usize) -> u32
+{
+    ((buf[off] as u32) << 24) | ((buf[off + 1] as u32) << 16) |
+        ((buf[off + 2] as u32) << 8) | (buf[off + 3] as u32)
+}
+
+fn be64(buf: &[u8], off: usize) -> u64
+{
+    ((be32(buf, off) as u64) << 32) | (be32(buf, off + 4) as u64)
+}
+
+pub fn read_be64(buf: &[u8], off: usize) -> u64
+{
+    be64(buf, off)
+}
+
+pub fn read_be16(buf: &[u8], off: usize) -> u16
+{
+    ((buf[off] as u16) << 8) | (buf[off + 1] as u16)
+}
+
+pub fn write_be32(buf: &mut [u8], off: usize, val: u32)
+{
+    buf[off] = (val >> 24) as u8;
+    buf[off + 1] = (val >> 16) as u8;
+    buf[off + 2] = (val >> 8) as u8;
+    buf[off + 3] = val as u8;
+}
+
+pub fn write_be64(buf: &mut [u8], off: usize, val: u64)
+{
+    write_be32(buf, off, (val >> 32) as u32);
+    write_be32(buf, off + 4, val as u32);
+}
+
+pub fn write_be16(buf: &mut [u8], off: usize, val: u16)
+{
+    buf[off] = (val >> 8) as u8;
+    buf[off + 1] = val as u8;
+}
+
+fn invalid(message: &str) -> BlockError
+{
+    BlockError::new(-EINVAL, format!("Invalid qcow2 image: {}", message))
+}
+
+
+/// The fixed-size part of the qcow2 header (v2 images stop here; v3 adds
+/// further fields we don't need yet).
+pub struct Header {
+    pub version: u32,
+    pub backing_file_offset: u64,
+    pub backing_file_size: u32,
+    pub cluster_bits: u32,
+    pub size: u64,
+    pub crypt_method: u32,
+    pub l1_size: u32,
+    pub l1_table_offset: u64,
+    pub refcount_table_offset: u64,
+    pub refcount_table_clusters: u32,
+    pub nb_snapshots: u32,
+    pub snapshots_offset: u64,
+}
+
+impl Header {
+    /// Parse the first 72 bytes of a qcow2 image (the common v2/v3
+    /// header); `buf` must be at least that long. For v3 images the
+    /// refcount_order field at offset 96 is checked as well, so `buf`
+    /// must then be at least 100 bytes long.
+    pub fn parse(buf: &[u8]) -> BlockResult<Header>
+    {
+        if buf.len() < 72 {
+            return Err(invalid("header truncated"));
+        }
+
+        if be32(buf, 0) != QCOW_MAGIC {
+            return Err(invalid("bad magic"));
+        }
+
+        let version = be32(buf, 4);
+        if version != 2 && version != 3 {
+            return Err(invalid("unsupported version"));
+        }
+
+        let cluster_bits = be32(buf, 20);
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(invalid("cluster size out of range"));
+        }
+
+        /* v2 images have no refcount_order field and always use the
+         * default (order 4, 16 bits per entry); v3 images store it at
+         * offset 96, and qcow2-rust only supports that same default. */
+        let refcount_order = if version == 3 {
+            if buf.len() < 100 {
+                return Err(invalid("header truncated"));
+            }
+            be32(buf, 96)
+        } else {
+            4
+        };
+
+        if refcount_order != 4 {
+            return Err(invalid("unsupported refcount_order (only the \
+                                 default of 4, i.e. 16-bit refcounts, is \
+                                 supported)"));
+        }
+
+        let header = Header {
+            version: version,
+            backing_file_offset: be64(buf, 8),
+            backing_file_size: be32(buf, 16),
+            cluster_bits: cluster_bits,
+            size: be64(buf, 24),
+            crypt_method: be32(buf, 32),
+            l1_size: be32(buf, 36),
+            l1_table_offset: be64(buf, 40),
+            refcount_table_offset: be64(buf, 48),
+            refcount_table_clusters: be32(buf, 56),
+            nb_snapshots: be32(buf, 60),
+            snapshots_offset: be64(buf, 64),
+        };
+
+        if header.crypt_method != 0 {
+            return Err(invalid("encrypted images are not supported"));
+        }
+
+        Ok(header)
+    }
+
+    pub fn cluster_size(&self) -> u64
+    {
+        1u64 << self.cluster_bits
+    }
+
+    /// Number of index bits into an L2 table (log2 of its entry count, see
+    /// `l2_entries()`): each entry is 8 bytes, and a full L2 table spans
+    /// exactly one cluster.
+    pub fn l2_bits(&self) -> u32
+    {
+        self.cluster_bits - 3
+    }
+
+    pub fn l2_entries(&self) -> u64
+    {
+        1u64 << self.l2_bits()
+    }
+
+    pub fn l1_index(&self, guest_offset: u64) -> u64
+    {
+        guest_offset >> (self.cluster_bits + self.l2_bits())
+    }
+
+    pub fn l2_index(&self, guest_offset: u64) -> u64
+    {
+        (guest_offset >> self.cluster_bits) & (self.l2_entries() - 1)
+    }
+}
+
+
+/// Decoded form of an allocated cluster, as pointed to by an L2 entry.
+pub enum Cluster {
+    Plain { host_offset: u64 },
+    /// Explicit zero cluster (v3 `QCOW_OFLAG_ZERO`): always reads as all
+    /// zeroes, regardless of any backing file. `host_offset` is 0 for a
+    /// sparse zero cluster, or the (still refcounted) preallocated
+    /// cluster backing it otherwise.
+    Zero { host_offset: u64 },
+    Compressed { file_offset: u64, size: usize, header_skip: usize },
+}
+
+/// Interpret a raw (big-endian-decoded) L2 entry. Returns `None` for an
+/// unallocated cluster (entry == 0, ignoring the COPIED flag).
+pub fn decode_l2_entry(header: &Header, entry: u64) -> BlockResult<Option<Cluster>>
+{
+    if entry & !QCOW_OFLAG_COPIED == 0 {
+        return Ok(None);
+    }
+
+    if entry & QCOW_OFLAG_COMPRESSED != 0 {
+        /* Layout (non-COPIED, non-COMPRESSED bits):
+         *   bits (x - 1) .. 0: host offset of the compressed data
+         *   bits 61 .. x: number of additional 512 byte sectors used,
+         *                 i.e. (size - 1) in units of a sector
+         * where x = 62 - (cluster_bits - 8). */
+        let x = 62 - (header.cluster_bits - 8);
+        let offset_mask = (1u64 << x) - 1;
+        let coffset = entry & offset_mask;
+        let nb_csectors = ((entry >> x) & ((1u64 << (62 - x)) - 1)) + 1;
+
+        let file_offset = coffset & !511u64;
+        let header_skip = (coffset & 511) as usize;
+        let size = (nb_csectors * 512) as usize - header_skip;
+
+        Ok(Some(Cluster::Compressed {
+            file_offset: file_offset,
+            size: size,
+            header_skip: header_skip,
+        }))
+    } else if entry & QCOW_OFLAG_ZERO != 0 {
+        let host_offset = entry & STD_OFFSET_MASK;
+        Ok(Some(Cluster::Zero { host_offset: host_offset }))
+    } else {
+        let host_offset = entry & STD_OFFSET_MASK;
+        Ok(Some(Cluster::Plain { host_offset: host_offset }))
+    }
+}
+
+/// Mask off the COPIED flag of an L1 entry to get the L2 table's host
+/// cluster offset (0 if there is no L2 table yet).
+pub fn l1_entry_l2_offset(entry: u64) -> u64
+{
+    entry & !QCOW_OFLAG_COPIED
+}
+
+/// Whether an L1 entry has any bit set that this driver doesn't
+/// understand: the COMPRESSED flag never applies to L1 entries, and
+/// bits 56..61 are reserved.
+pub fn l1_entry_has_reserved_bits(entry: u64) -> bool
+{
+    entry & (QCOW_OFLAG_COMPRESSED | RESERVED_BITS_MASK) != 0
+}
+
+/// Whether a plain (non-compressed) L2 entry has any of its reserved
+/// bits (56..61) set.
+pub fn l2_entry_has_reserved_bits(entry: u64) -> bool
+{
+    let host_offset = entry & !(QCOW_OFLAG_COPIED | QCOW_OFLAG_COMPRESSED);
+    host_offset & RESERVED_BITS_MASK != 0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_header(cluster_bits: u32) -> Header
+    {
+        Header {
+            version: 3,
+            backing_file_offset: 0,
+            backing_file_size: 0,
+            cluster_bits: cluster_bits,
+            size: 0,
+            crypt_method: 0,
+            l1_size: 0,
+            l1_table_offset: 0,
+            refcount_table_offset: 0,
+            refcount_table_clusters: 0,
+            nb_snapshots: 0,
+            snapshots_offset: 0,
+        }
+    }
+
+    #[test]
+    fn be_round_trips()
+    {
+        let mut buf = vec![0u8; 16];
+        write_be32(&mut buf, 0, 0x1234_5678);
+        assert_eq!(be32(&buf, 0), 0x1234_5678);
+
+        write_be64(&mut buf, 0, 0x0123_4567_89ab_cdef);
+        assert_eq!(read_be64(&buf, 0), 0x0123_4567_89ab_cdef);
+
+        write_be16(&mut buf, 8, 0xbeef);
+        assert_eq!(read_be16(&buf, 8), 0xbeef);
+    }
+
+    #[test]
+    fn header_parse_rejects_truncated_buffer()
+    {
+        let buf = vec![0u8; 71];
+        assert!(Header::parse(&buf).is_err());
+    }
+
+    #[test]
+    fn header_parse_rejects_bad_magic()
+    {
+        let buf = vec![0u8; 72];
+        assert!(Header::parse(&buf).is_err());
+    }
+
+    #[test]
+    fn header_parse_rejects_unsupported_version()
+    {
+        let mut buf = vec![0u8; 72];
+        write_be32(&mut buf, 0, QCOW_MAGIC);
+        write_be32(&mut buf, 4, 1);
+        write_be32(&mut buf, 20, 16);
+        assert!(Header::parse(&buf).is_err());
+    }
+
+    #[test]
+    fn header_parse_rejects_out_of_range_cluster_bits()
+    {
+        let mut buf = vec![0u8; 72];
+        write_be32(&mut buf, 0, QCOW_MAGIC);
+        write_be32(&mut buf, 4, 3);
+        write_be32(&mut buf, 20, MAX_CLUSTER_BITS + 1);
+        assert!(Header::parse(&buf).is_err());
+    }
+
+    #[test]
+    fn header_parse_accepts_valid_v3_header()
+    {
+        let mut buf = vec![0u8; 104];
+        write_be32(&mut buf, 0, QCOW_MAGIC);
+        write_be32(&mut buf, 4, 3);
+        write_be64(&mut buf, 24, 0x1_0000_0000);
+        write_be32(&mut buf, 20, 16);
+        write_be32(&mut buf, 36, 1);
+        write_be64(&mut buf, 40, 0x1_0000);
+        write_be32(&mut buf, 96, 4); /* refcount_order */
+
+        let header = Header::parse(&buf).unwrap();
+        assert_eq!(header.version, 3);
+        assert_eq!(header.cluster_bits, 16);
+        assert_eq!(header.size, 0x1_0000_0000);
+        assert_eq!(header.l1_size, 1);
+        assert_eq!(header.l1_table_offset, 0x1_0000);
+        assert_eq!(header.cluster_size(), 0x1_0000);
+    }
+
+    #[test]
+    fn header_parse_rejects_v3_header_truncated_before_refcount_order()
+    {
+        let mut buf = vec![0u8; 99];
+        write_be32(&mut buf, 0, QCOW_MAGIC);
+        write_be32(&mut buf, 4, 3);
+        write_be32(&mut buf, 20, 16);
+        assert!(Header::parse(&buf).is_err());
+    }
+
+    #[test]
+    fn header_parse_rejects_unsupported_refcount_order()
+    {
+        let mut buf = vec![0u8; 104];
+        write_be32(&mut buf, 0, QCOW_MAGIC);
+        write_be32(&mut buf, 4, 3);
+        write_be32(&mut buf, 20, 16);
+        write_be32(&mut buf, 96, 3); /* refcount_order != 4 */
+        assert!(Header::parse(&buf).is_err());
+    }
+
+    #[test]
+    fn header_parse_v2_ignores_refcount_order_field()
+    {
+        /* v2 has no refcount_order field at all; the default (order 4)
+         * always applies regardless of what garbage sits at that byte
+         * offset in a v2 image. */
+        let mut buf = vec![0u8; 104];
+        write_be32(&mut buf, 0, QCOW_MAGIC);
+        write_be32(&mut buf, 4, 2);
+        write_be32(&mut buf, 20, 16);
+        write_be32(&mut buf, 96, 0xdead_beef);
+        assert!(Header::parse(&buf).is_ok());
+    }
+
+    #[test]
+    fn decode_l2_entry_unallocated()
+    {
+        let header = test_header(16);
+        assert!(decode_l2_entry(&header, 0).unwrap().is_none());
+        /* The COPIED flag alone doesn't mean a cluster is allocated. */
+        assert!(decode_l2_entry(&header, QCOW_OFLAG_COPIED).unwrap().is_none());
+    }
+
+    #[test]
+    fn decode_l2_entry_plain()
+    {
+        let header = test_header(16);
+        let entry = QCOW_OFLAG_COPIED | 0x20_0000;
+        match decode_l2_entry(&header, entry).unwrap() {
+            Some(Cluster::Plain { host_offset }) => assert_eq!(host_offset, 0x20_0000),
+            _ => panic!("expected Cluster::Plain"),
+        }
+    }
+
+    #[test]
+    fn decode_l2_entry_plain_masks_reserved_bits()
+    {
+        let header = test_header(16);
+        /* Bits 56..61 are reserved and must not leak into host_offset. */
+        let entry = QCOW_OFLAG_COPIED | RESERVED_BITS_MASK | 0x20_0000;
+        match decode_l2_entry(&header, entry).unwrap() {
+            Some(Cluster::Plain { host_offset }) => assert_eq!(host_offset, 0x20_0000),
+            _ => panic!("expected Cluster::Plain"),
+        }
+    }
+
+    #[test]
+    fn decode_l2_entry_zero_cluster()
+    {
+        let header = test_header(16);
+
+        /* Sparse zero cluster: no host cluster backs it. */
+        let entry = QCOW_OFLAG_COPIED | QCOW_OFLAG_ZERO;
+        match decode_l2_entry(&header, entry).unwrap() {
+            Some(Cluster::Zero { host_offset }) => assert_eq!(host_offset, 0),
+            _ => panic!("expected Cluster::Zero"),
+        }
+
+        /* Preallocated zero cluster: still refcounted at host_offset. */
+        let entry = QCOW_OFLAG_COPIED | QCOW_OFLAG_ZERO | 0x20_0000;
+        match decode_l2_entry(&header, entry).unwrap() {
+            Some(Cluster::Zero { host_offset }) => assert_eq!(host_offset, 0x20_0000),
+            _ => panic!("expected Cluster::Zero"),
+        }
+    }
+
+    #[test]
+    fn decode_l2_entry_compressed()
+    {
+        /* x = 62 - (cluster_bits - 8) = 62 - (16 - 8) = 54. */
+        let header = test_header(16);
+        let coffset = 0x10_0100u64; /* host_offset 0x10_0000, header_skip 0x100 */
+        let nb_csectors_minus_one = 2u64; /* 2 additional 512-byte sectors, 3 in total */
+        let entry = QCOW_OFLAG_COMPRESSED | (nb_csectors_minus_one << 54) | coffset;
+
+        match decode_l2_entry(&header, entry).unwrap() {
+            Some(Cluster::Compressed { file_offset, size, header_skip }) => {
+                assert_eq!(file_offset, 0x10_0000);
+                assert_eq!(header_skip, 0x100);
+                assert_eq!(size, 3 * 512 - 0x100);
+            }
+            _ => panic!("expected Cluster::Compressed"),
+        }
+    }
+
+    #[test]
+    fn l1_entry_l2_offset_masks_copied_flag()
+    {
+        assert_eq!(l1_entry_l2_offset(QCOW_OFLAG_COPIED | 0x30_0000), 0x30_0000);
+    }
+
+    #[test]
+    fn reserved_bits_detection()
+    {
+        assert!(l1_entry_has_reserved_bits(RESERVED_BITS_MASK));
+        assert!(l1_entry_has_reserved_bits(QCOW_OFLAG_COMPRESSED));
+        assert!(!l1_entry_has_reserved_bits(QCOW_OFLAG_COPIED | 0x30_0000));
+
+        assert!(l2_entry_has_reserved_bits(RESERVED_BITS_MASK));
+        assert!(!l2_entry_has_reserved_bits(QCOW_OFLAG_COPIED | 0x30_0000));
+    }
+}
@@ -1,27 +1,266 @@
-use libc::{c_int,ENOTSUP};
-use c_interface::*;
+use libc::{c_int,EINVAL,ENOTSUP};
+use std::cmp;
+use std::io::Read;
 
+use flate2::read::ZlibDecoder;
 
-extern fn qcow2_open(_: *mut BlockDriverState, _: *mut QDict, _: c_int,
-                     errp: *mut *mut Error)
-    -> c_int
-{
-    error_setg(errp, String::from("Thank you for using Rust"));
-    return -ENOTSUP;
+use c_interface::{self,BdrvCheckResult,BlockDriverOps,BlockError,BlockResult,
+                  BlockStatus,Bs,Child,CreateOpts,QDict,QemuOptDesc};
+
+mod cache;
+mod check;
+mod create;
+mod format;
+
+use self::cache::L2Cache;
+use self::format::{Cluster,Header};
+
+
+/* Keep this many decoded L2 tables around; enough to cover a handful of
+ * hot regions without unbounded growth. */
+const L2_CACHE_ENTRIES: usize = 8;
+
+
+pub struct Qcow2State {
+    header: Header,
+    l1_table: Vec<u64>,
+    l2_cache: L2Cache,
+    backing: Option<Child>,
 }
 
-extern fn qcow2_close(_: *mut BlockDriverState)
-{
+impl Qcow2State {
+    fn read_l2_table(&mut self, bs: &mut Bs, table_offset: u64)
+        -> BlockResult<&Vec<u64>>
+    {
+        if self.l2_cache.get(table_offset).is_none() {
+            let cluster_size = self.header.cluster_size() as usize;
+            let mut raw = vec![0u8; cluster_size];
+            bs.read_from_file(table_offset as i64, &mut raw)?;
+
+            let entries = (cluster_size / 8) as u64;
+            let table = (0..entries).map(|i| {
+                let off = (i * 8) as usize;
+                ((raw[off] as u64) << 56) | ((raw[off + 1] as u64) << 48) |
+                    ((raw[off + 2] as u64) << 40) | ((raw[off + 3] as u64) << 32) |
+                    ((raw[off + 4] as u64) << 24) | ((raw[off + 5] as u64) << 16) |
+                    ((raw[off + 6] as u64) << 8) | (raw[off + 7] as u64)
+            }).collect();
+
+            self.l2_cache.insert(table_offset, table);
+        }
+
+        Ok(self.l2_cache.get(table_offset).unwrap())
+    }
+
+    /// Resolve a guest offset to the cluster backing it, or `None` if
+    /// the cluster is unallocated.
+    fn lookup_cluster(&mut self, bs: &mut Bs, guest_offset: u64)
+        -> BlockResult<Option<Cluster>>
+    {
+        let l1_index = self.header.l1_index(guest_offset) as usize;
+        if l1_index >= self.l1_table.len() {
+            return Err(BlockError::new(-EINVAL,
+                String::from("Guest offset is beyond the image's L1 table")));
+        }
+
+        let l2_table_offset = format::l1_entry_l2_offset(self.l1_table[l1_index]);
+        if l2_table_offset == 0 {
+            return Ok(None);
+        }
+
+        let l2_index = self.header.l2_index(guest_offset) as usize;
+        let l2_entry = {
+            let l2_table = self.read_l2_table(bs, l2_table_offset)?;
+            l2_table[l2_index]
+        };
+
+        format::decode_l2_entry(&self.header, l2_entry)
+    }
+
+    fn read_cluster_range(&mut self, bs: &mut Bs, guest_offset: u64,
+                          buf: &mut [u8])
+        -> BlockResult<()>
+    {
+        match self.lookup_cluster(bs, guest_offset)? {
+            None => {
+                /* Unallocated: defer to the backing file, if any, at
+                 * the same guest offset; otherwise this reads as
+                 * zeroes. */
+                match self.backing {
+                    Some(ref backing) => backing.read(guest_offset as i64, buf),
+                    None => {
+                        for b in buf.iter_mut() {
+                            *b = 0;
+                        }
+                        Ok(())
+                    }
+                }
+            }
+
+            Some(Cluster::Zero { .. }) => {
+                /* Explicit zero cluster: always reads as zeroes, even
+                 * with a backing file. */
+                for b in buf.iter_mut() {
+                    *b = 0;
+                }
+                Ok(())
+            }
+
+            Some(Cluster::Plain { host_offset }) => {
+                let cluster_offset = guest_offset & (self.header.cluster_size() - 1);
+                bs.read_from_file((host_offset + cluster_offset) as i64, buf)
+            }
+
+            Some(Cluster::Compressed { file_offset, size, header_skip }) => {
+                let mut compressed = vec![0u8; header_skip + size];
+                bs.read_from_file(file_offset as i64, &mut compressed)?;
+
+                let mut decompressed = vec![0u8; self.header.cluster_size() as usize];
+                let mut decoder = ZlibDecoder::new(&compressed[header_skip..]);
+                decoder.read_exact(&mut decompressed).map_err(|e| {
+                    BlockError::new(-EINVAL,
+                        format!("Failed to decompress cluster: {}", e))
+                })?;
+
+                let cluster_offset = (guest_offset &
+                                      (self.header.cluster_size() - 1)) as usize;
+                buf.copy_from_slice(
+                    &decompressed[cluster_offset..(cluster_offset + buf.len())]);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl BlockDriverOps for Qcow2State {
+    const SUPPORTS_BACKING: bool = true;
+
+    fn open(bs: &mut Bs, _options: &mut QDict, _flags: c_int)
+        -> BlockResult<Qcow2State>
+    {
+        /* 104 bytes covers the v3 header extension (up through
+         * refcount_order at offset 96..100); v2 images are shorter but
+         * their header cluster is always at least this big. */
+        let mut header_buf = [0u8; 104];
+        bs.read_from_file(0, &mut header_buf)?;
+        let header = Header::parse(&header_buf)?;
+
+        let mut l1_raw = vec![0u8; header.l1_size as usize * 8];
+        if !l1_raw.is_empty() {
+            bs.read_from_file(header.l1_table_offset as i64, &mut l1_raw)?;
+        }
+        let l1_table = (0..header.l1_size as usize).map(|i| {
+            let off = i * 8;
+            ((l1_raw[off] as u64) << 56) | ((l1_raw[off + 1] as u64) << 48) |
+                ((l1_raw[off + 2] as u64) << 40) | ((l1_raw[off + 3] as u64) << 32) |
+                ((l1_raw[off + 4] as u64) << 24) | ((l1_raw[off + 5] as u64) << 16) |
+                ((l1_raw[off + 6] as u64) << 8) | (l1_raw[off + 7] as u64)
+        }).collect();
+
+        let backing = if header.backing_file_offset != 0 {
+            let mut name_buf = vec![0u8; header.backing_file_size as usize];
+            bs.read_from_file(header.backing_file_offset as i64, &mut name_buf)?;
+            let name = String::from_utf8(name_buf).map_err(|_| {
+                BlockError::new(-EINVAL,
+                    String::from("Backing file name is not valid UTF-8"))
+            })?;
+
+            Some(bs.open_backing_child(&name)?)
+        } else {
+            None
+        };
+
+        Ok(Qcow2State {
+            header: header,
+            l1_table: l1_table,
+            l2_cache: L2Cache::new(L2_CACHE_ENTRIES),
+            backing: backing,
+        })
+    }
+
+    fn co_preadv(&mut self, bs: &mut Bs, offset: u64,
+                iov: &mut c_interface::IoVector)
+        -> BlockResult<()>
+    {
+        let mut buf = vec![0u8; iov.len()];
+        let cluster_size = self.header.cluster_size();
+
+        let mut pos = offset;
+        let mut done = 0;
+        while done < buf.len() {
+            let offset_in_cluster = pos & (cluster_size - 1);
+            let chunk = cmp::min(cluster_size - offset_in_cluster,
+                                 (buf.len() - done) as u64) as usize;
+
+            self.read_cluster_range(bs, pos, &mut buf[done..(done + chunk)])?;
+
+            pos += chunk as u64;
+            done += chunk;
+        }
+
+        iov.copy_from(&buf);
+        Ok(())
+    }
+
+    fn co_pwritev(&mut self, _bs: &mut Bs, _offset: u64,
+                 _iov: &mut c_interface::IoVector)
+        -> BlockResult<()>
+    {
+        Err(BlockError::new(-ENOTSUP,
+                            String::from("qcow2-rust is currently read-only")))
+    }
+
+    fn co_get_block_status(&mut self, bs: &mut Bs, sector_num: i64,
+                           nb_sectors: c_int)
+        -> BlockResult<BlockStatus>
+    {
+        let guest_offset = (sector_num as u64) * 512;
+        let cluster_size = self.header.cluster_size();
+
+        /* An explicit zero cluster always reads as zero (data present,
+         * but known to be zero). An unallocated cluster only reads as
+         * zero if there's no backing file to defer to; with one, the
+         * generic layer must recurse into it rather than assume zero. */
+        let (data, zero) = match self.lookup_cluster(bs, guest_offset)? {
+            None => (false, self.backing.is_none()),
+            Some(Cluster::Zero { .. }) => (true, true),
+            Some(Cluster::Plain { .. }) | Some(Cluster::Compressed { .. }) => (true, false),
+        };
+
+        /* The whole request is covered by a single cluster lookup if it
+         * doesn't cross a cluster boundary; otherwise conservatively
+         * report just up to the end of this cluster. */
+        let offset_in_cluster = guest_offset & (cluster_size - 1);
+        let remaining_in_cluster = cluster_size - offset_in_cluster;
+        let pnum = cmp::min(nb_sectors as u64,
+                            remaining_in_cluster / 512) as c_int;
+
+        Ok(BlockStatus {
+            pnum: cmp::max(pnum, 1),
+            data: data,
+            zero: zero,
+        })
+    }
+
+    fn create_opts() -> Vec<QemuOptDesc>
+    {
+        create::create_opts()
+    }
+
+    fn create(filename: &str, opts: &CreateOpts) -> BlockResult<()>
+    {
+        create::create(filename, opts)
+    }
+
+    fn check(&mut self, bs: &mut Bs, fix: bool) -> BlockResult<BdrvCheckResult>
+    {
+        check::check(&self.header, &self.l1_table, bs, fix)
+    }
 }
 
 
 #[no_mangle]
 pub extern fn bdrv_qcow2_rust_init()
 {
-    let mut bdrv = BlockDriver::new("qcow2-rust\0", 0);
-
-    bdrv.bdrv_open = Some(qcow2_open);
-    bdrv.bdrv_close = Some(qcow2_close);
-
-    bdrv_register(bdrv);
+    c_interface::register::<Qcow2State>("qcow2-rust\0");
 }